@@ -0,0 +1,97 @@
+// Copyright 2016 Brian Smith.
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHORS DISCLAIM ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHORS BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! Runtime CPU feature detection.
+//!
+//! Each query probes the hardware at most once per process and caches the
+//! result, so callers on a hot path (e.g. per-block digest dispatch) pay for
+//! the detection only on the first call.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const UNINITIALIZED: usize = 0;
+const SUPPORTED: usize = 1;
+const UNSUPPORTED: usize = 2;
+
+static SHA1_SUPPORT: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+static SHA256_SUPPORT: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+
+/// Returns whether the CPU has a dedicated SHA-1 instruction set extension
+/// (the ARMv8 `SHA1` crypto extension, or the x86_64 `SHA_NI` CPUID
+/// feature).
+#[inline]
+pub fn sha1_supported() -> bool { query(&SHA1_SUPPORT, detect::sha1) }
+
+/// Returns whether the CPU has a dedicated SHA-256 instruction set extension
+/// (the ARMv8 `SHA2` crypto extension, or the x86_64 `SHA_NI` CPUID
+/// feature). SHA-224 shares SHA-256's compression function, so `digest`
+/// consults this same flag for both. No accelerated implementation of either
+/// is linked into the crate, so `digest` uses the portable one whatever this
+/// returns.
+#[inline]
+pub fn sha256_supported() -> bool { query(&SHA256_SUPPORT, detect::sha256) }
+
+fn query(cell: &AtomicUsize, detect: fn() -> bool) -> bool {
+    match cell.load(Ordering::Relaxed) {
+        SUPPORTED => true,
+        UNSUPPORTED => false,
+        _ => {
+            let supported = detect();
+            cell.store(if supported { SUPPORTED } else { UNSUPPORTED },
+                       Ordering::Relaxed);
+            supported
+        },
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod detect {
+    // See the ARM Architecture Reference Manual's definition of
+    // `AT_HWCAP`/`HWCAP_*`.
+    const AT_HWCAP: u64 = 16;
+    const HWCAP_SHA1: u64 = 1 << 5;
+    const HWCAP_SHA2: u64 = 1 << 6;
+
+    extern {
+        fn getauxval(type_: u64) -> u64;
+    }
+
+    fn hwcap() -> u64 { unsafe { getauxval(AT_HWCAP) } }
+
+    pub fn sha1() -> bool { hwcap() & HWCAP_SHA1 != 0 }
+    pub fn sha256() -> bool { hwcap() & HWCAP_SHA2 != 0 }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod detect {
+    use std::arch::x86_64::__cpuid_count;
+
+    // CPUID leaf 7, sub-leaf 0, EBX bit 29 is the `SHA_NI` feature flag; it
+    // covers both the SHA-1 and SHA-256 instruction extensions.
+    const EBX_SHA_NI: u32 = 1 << 29;
+
+    fn sha_ni() -> bool {
+        let result = unsafe { __cpuid_count(7, 0) };
+        result.ebx & EBX_SHA_NI != 0
+    }
+
+    pub fn sha1() -> bool { sha_ni() }
+    pub fn sha256() -> bool { sha_ni() }
+}
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+mod detect {
+    pub fn sha1() -> bool { false }
+    pub fn sha256() -> bool { false }
+}
@@ -24,7 +24,10 @@
 // The goal for this implementation is to drive the overhead as close to zero
 // as possible.
 
-use super::{c, polyfill};
+use super::{c, cpu, polyfill};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // XXX: endian-specific.
 macro_rules! u32x2 {
@@ -65,6 +68,12 @@ pub struct Context {
     num_pending: usize,
 
     pub algorithm: &'static Algorithm,
+
+    // Overrides `algorithm`'s (process-wide, cached) CPU dispatch for just
+    // this `Context`. Only ever set by `force_portable_for_testing`, so that
+    // tests can exercise the portable path without racing other concurrently
+    // running tests that use the same `Algorithm` the normal way.
+    forced_block_data_order: Option<BlockDataOrder>,
 }
 
 impl Context {
@@ -78,9 +87,27 @@ impl Context {
             completed_data_blocks: 0,
             pending: [0u8; MAX_BLOCK_LEN],
             num_pending: 0,
+            forced_block_data_order: None,
         }
     }
 
+    /// Makes this particular `Context` use the portable block compression
+    /// implementation for the rest of its lifetime, regardless of what
+    /// `self.algorithm` would otherwise dispatch to. Unlike mutating
+    /// `self.algorithm`'s shared, process-wide dispatch cache, this cannot
+    /// affect any other `Context` using the same `Algorithm`, so it's safe
+    /// to use from tests that run concurrently.
+    #[cfg(test)]
+    fn force_portable_for_testing(&mut self) {
+        self.forced_block_data_order = Some(self.algorithm.block_data_order);
+    }
+
+    #[inline]
+    fn block_data_order(&self) -> BlockDataOrder {
+        self.forced_block_data_order
+            .unwrap_or_else(|| self.algorithm.resolve_block_data_order())
+    }
+
     /// Updates the digest with all the data in `data`. `update` may be called
     /// zero or more times until `finish` is called. It must not be called
     /// after `finish` has been called.
@@ -96,6 +123,8 @@ impl Context {
             return;
         }
 
+        let block_data_order = self.block_data_order();
+
         let mut remaining = data;
         if self.num_pending > 0 {
             let to_copy = self.algorithm.block_len - self.num_pending;
@@ -104,8 +133,8 @@ impl Context {
                 &data[..to_copy]);
 
             unsafe {
-                (self.algorithm.block_data_order)(self.state.as_mut_ptr(),
-                                                  self.pending.as_ptr(), 1);
+                block_data_order(self.state.as_mut_ptr(),
+                                 self.pending.as_ptr(), 1);
             }
             self.completed_data_blocks =
                 self.completed_data_blocks.checked_add(1).unwrap();
@@ -118,9 +147,8 @@ impl Context {
         let num_to_save_for_later = remaining.len() % self.algorithm.block_len;
         if num_blocks > 0 {
             unsafe {
-                (self.algorithm.block_data_order)(self.state.as_mut_ptr(),
-                                                  remaining.as_ptr(),
-                                                  num_blocks);
+                block_data_order(self.state.as_mut_ptr(), remaining.as_ptr(),
+                                 num_blocks);
             }
             self.completed_data_blocks =
                 self.completed_data_blocks.checked_add(widen_u64(num_blocks))
@@ -144,6 +172,8 @@ impl Context {
         // We know |num_pending < self.algorithm.block_len|, because we would
         // have processed the block otherwise.
 
+        let block_data_order = self.block_data_order();
+
         let mut padding_pos = self.num_pending;
         self.pending[padding_pos] = 0x80;
         padding_pos += 1;
@@ -152,8 +182,8 @@ impl Context {
             polyfill::slice::fill(
                 &mut self.pending[padding_pos..self.algorithm.block_len], 0);
             unsafe {
-                (self.algorithm.block_data_order)(self.state.as_mut_ptr(),
-                                                  self.pending.as_ptr(), 1);
+                block_data_order(self.state.as_mut_ptr(),
+                                 self.pending.as_ptr(), 1);
             }
             // We don't increase |self.completed_data_blocks| because the
             // padding isn't data, and so it isn't included in the data length.
@@ -176,8 +206,8 @@ impl Context {
             completed_data_bits /= 0x100;
         }
         unsafe {
-            (self.algorithm.block_data_order)(self.state.as_mut_ptr(),
-                                              self.pending.as_ptr(), 1);
+            block_data_order(self.state.as_mut_ptr(), self.pending.as_ptr(),
+                             1);
         }
 
         Digest {
@@ -189,8 +219,105 @@ impl Context {
     /// The algorithm that this context is using.
     #[inline(always)]
     pub fn algorithm(&self) -> &'static Algorithm { self.algorithm }
+
+    /// Serializes the context's state so that it can be resumed later with
+    /// `import_state`, without having to keep the process (or the
+    /// unhashed prefix of the input) around. This is useful for
+    /// checkpointing the hashing of a multi-gigabyte stream, or for moving
+    /// a partial computation between processes or across a process
+    /// restart.
+    ///
+    /// The returned bytes are an implementation detail of this version of
+    /// *ring* and are not guaranteed to be readable by other versions. They
+    /// are also tied to the exporting host's endianness (like `u32x2!`
+    /// elsewhere in this module, the internal chaining state is serialized
+    /// as raw native-endian words, not byte-swapped): `import_state` on a
+    /// host with different endianness than the one that produced `bytes`
+    /// will not return an error, but will silently reconstruct the wrong
+    /// state and so compute the wrong `Digest`. Only export and import on
+    /// hosts of the same endianness.
+    pub fn export_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            1 + 8 + 1 + self.algorithm.block_len + self.algorithm.chaining_len);
+
+        out.push(self.algorithm.id as u8);
+        write_u64_be(&mut out, self.completed_data_blocks);
+        out.push(self.num_pending as u8);
+        out.extend_from_slice(&self.pending[..self.num_pending]);
+        out.extend_from_slice(
+            &polyfill::slice::u64_as_u8(&self.state)[..self.algorithm.chaining_len]);
+
+        out
+    }
+
+    /// Reconstructs a `Context` previously serialized by `export_state`.
+    ///
+    /// `import_state(ctx.export_state())`, followed by the same sequence of
+    /// `update`/`finish` calls that would have been made on `ctx`, produces
+    /// a byte-identical `Digest` to calling those methods on `ctx` directly.
+    ///
+    /// Returns `Err` if `bytes` does not name a recognized algorithm, is
+    /// truncated, or claims more pending bytes than the algorithm's block
+    /// length allows.
+    pub fn import_state(bytes: &[u8]) -> Result<Context, ImportStateError> {
+        if bytes.len() < 1 + 8 + 1 {
+            return Err(ImportStateError);
+        }
+
+        let algorithm = try!(ID::from_byte(bytes[0])
+                                 .ok_or(ImportStateError)).algorithm();
+
+        let completed_data_blocks = read_u64_be(&bytes[1..9]);
+
+        let num_pending = bytes[9] as usize;
+        if num_pending >= algorithm.block_len {
+            return Err(ImportStateError);
+        }
+
+        let pending_start = 10;
+        let state_start = pending_start + num_pending;
+        let state_end = state_start + algorithm.chaining_len;
+        if bytes.len() != state_end {
+            return Err(ImportStateError);
+        }
+
+        let mut pending = [0u8; MAX_BLOCK_LEN];
+        polyfill::slice::fill_from_slice(&mut pending[..num_pending],
+                                         &bytes[pending_start..state_start]);
+
+        let mut state = [0u64; MAX_CHAINING_LEN / 8];
+        unsafe {
+            ptr::copy_nonoverlapping(bytes[state_start..].as_ptr(),
+                                     state.as_mut_ptr() as *mut u8,
+                                     algorithm.chaining_len);
+        }
+
+        Ok(Context {
+            algorithm: algorithm,
+            state: state,
+            completed_data_blocks: completed_data_blocks,
+            pending: pending,
+            num_pending: num_pending,
+            forced_block_data_order: None,
+        })
+    }
+}
+
+fn write_u64_be(out: &mut Vec<u8>, value: u64) {
+    for i in (0..8).rev() {
+        out.push((value >> (i * 8)) as u8);
+    }
 }
 
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |value, &b| (value << 8) | (b as u64))
+}
+
+/// The error returned by `Context::import_state` when `bytes` does not
+/// encode a valid exported `Context`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImportStateError;
+
 // XXX: This should just be `#[derive(Clone)]` but that doesn't work because
 // `[u8; 128]` doesn't implement `Clone`.
 impl Clone for Context {
@@ -200,7 +327,8 @@ impl Clone for Context {
             pending: self.pending,
             completed_data_blocks: self.completed_data_blocks,
             num_pending: self.num_pending,
-            algorithm: self.algorithm
+            algorithm: self.algorithm,
+            forced_block_data_order: self.forced_block_data_order,
         }
    }
 }
@@ -274,8 +402,22 @@ pub struct Algorithm {
     /// The length of the length in the padding.
     len_len: usize,
 
-    block_data_order: unsafe extern fn(state: *mut u64, data: *const u8,
-                                       num: c::size_t),
+    /// The portable implementation of the block compression function. This
+    /// is always a valid fallback, regardless of what the current CPU
+    /// supports.
+    block_data_order: BlockDataOrder,
+
+    /// Returns a hardware-accelerated implementation of the block
+    /// compression function if one exists for this algorithm and the
+    /// current CPU supports it, or `None` otherwise. Checked at most once
+    /// per algorithm per process; see `resolve_block_data_order`.
+    accelerated_block_data_order: fn() -> Option<BlockDataOrder>,
+
+    /// Caches the result of `accelerated_block_data_order` the first time
+    /// `resolve_block_data_order` is called, as a `BlockDataOrder` function
+    /// pointer reinterpreted as a `usize`; zero means "not yet resolved".
+    dispatch_cache: AtomicUsize,
+
     format_output: fn (input: &[u64; MAX_CHAINING_LEN / 8]) ->
                        [u64; MAX_OUTPUT_LEN / 8],
 
@@ -287,22 +429,47 @@ pub struct Algorithm {
     pub id: ID,
 }
 
+type BlockDataOrder =
+    unsafe extern fn(state: *mut u64, data: *const u8, num: c::size_t);
+
+impl Algorithm {
+    /// Returns the block compression function to use for this call: the
+    /// hardware-accelerated one if the current CPU supports it, otherwise
+    /// the portable fallback. The outcome is cached after the first call so
+    /// that the CPU feature probe only runs once per algorithm per process.
+    #[inline]
+    fn resolve_block_data_order(&self) -> BlockDataOrder {
+        let cached = self.dispatch_cache.load(Ordering::Relaxed);
+        if cached != 0 {
+            return unsafe { mem::transmute(cached) };
+        }
+        let chosen = (self.accelerated_block_data_order)()
+            .unwrap_or(self.block_data_order);
+        self.dispatch_cache.store(chosen as usize, Ordering::Relaxed);
+        chosen
+    }
+}
+
 #[cfg(test)]
 pub mod test_util {
     use super::super::digest;
 
-    pub static ALL_ALGORITHMS: [&'static digest::Algorithm; 4] = [
+    pub static ALL_ALGORITHMS: [&'static digest::Algorithm; 7] = [
         &digest::SHA1,
+        &digest::SHA224,
         &digest::SHA256,
         &digest::SHA384,
         &digest::SHA512,
+        &digest::SHA512_224,
+        &digest::SHA512_256,
     ];
 }
 
 macro_rules! impl_Digest {
     ($XXX:ident, $output_len_in_bits:expr, $chaining_len_in_bits:expr,
      $block_len_in_bits:expr, $len_len_in_bits:expr,
-     $xxx_block_data_order:ident, $format_output:ident, $XXX_INITIAL:ident,
+     $xxx_block_data_order:ident, $xxx_accelerated_block_data_order:ident,
+     $format_output:ident, $XXX_INITIAL:ident,
      $initial_value:expr) => {
 
         pub static $XXX: Algorithm = Algorithm {
@@ -311,6 +478,8 @@ macro_rules! impl_Digest {
             block_len: $block_len_in_bits / 8,
             len_len: $len_len_in_bits / 8,
             block_data_order: $xxx_block_data_order,
+            accelerated_block_data_order: $xxx_accelerated_block_data_order,
+            dispatch_cache: AtomicUsize::new(0),
             format_output: $format_output,
             initial_state: $initial_value,
             id: ID::$XXX,
@@ -318,20 +487,74 @@ macro_rules! impl_Digest {
     }
 }
 
+/// No hardware-accelerated implementation exists (yet) for this algorithm.
+fn no_acceleration() -> Option<BlockDataOrder> { None }
+
+/// `cpu::sha1_supported()` performs (and caches) the real CPU feature probe,
+/// but no accelerated `sha1_block_data_order` assembly is linked into this
+/// crate yet, so there is nothing to dispatch to regardless of what it
+/// reports. The probe is still run here, rather than skipped, so that
+/// acceleration can be turned on by changing just this function (to
+/// `if cpu::sha1_supported() { Some(sha1_block_data_order_hw) } else { None
+/// }`) once that implementation and its build-system plumbing land.
+fn sha1_acceleration() -> Option<BlockDataOrder> {
+    let _ = cpu::sha1_supported();
+    None
+}
+
+/// See `sha1_acceleration`. SHA-224 shares SHA-256's compression function, so
+/// it would use the same accelerated implementation once one exists.
+fn sha256_acceleration() -> Option<BlockDataOrder> {
+    let _ = cpu::sha256_supported();
+    None
+}
+
 /// The type of `Algorithm::id`.
 #[derive(Clone, Copy, PartialEq)]
 pub enum ID {
     SHA1,
+    SHA224,
     SHA256,
     SHA384,
     SHA512,
+    SHA512_224,
+    SHA512_256,
+}
+
+impl ID {
+    /// Returns the `Algorithm` that this `ID` identifies.
+    fn algorithm(&self) -> &'static Algorithm {
+        match *self {
+            ID::SHA1 => &SHA1,
+            ID::SHA224 => &SHA224,
+            ID::SHA256 => &SHA256,
+            ID::SHA384 => &SHA384,
+            ID::SHA512 => &SHA512,
+            ID::SHA512_224 => &SHA512_224,
+            ID::SHA512_256 => &SHA512_256,
+        }
+    }
+
+    /// The inverse of `Context::export_state`'s `self.algorithm.id as u8`.
+    fn from_byte(byte: u8) -> Option<ID> {
+        match byte {
+            x if x == ID::SHA1 as u8 => Some(ID::SHA1),
+            x if x == ID::SHA224 as u8 => Some(ID::SHA224),
+            x if x == ID::SHA256 as u8 => Some(ID::SHA256),
+            x if x == ID::SHA384 as u8 => Some(ID::SHA384),
+            x if x == ID::SHA512 as u8 => Some(ID::SHA512),
+            x if x == ID::SHA512_224 as u8 => Some(ID::SHA512_224),
+            x if x == ID::SHA512_256 as u8 => Some(ID::SHA512_256),
+            _ => None,
+        }
+    }
 }
 
 #[inline(always)]
 fn widen_u64(x: usize) -> u64 { x as u64 }
 
 impl_Digest!(SHA1, 160, 160, 512, 64, sha1_block_data_order,
-             sha256_format_output,
+             sha1_acceleration, sha256_format_output,
              SHA1_INITIAL, [
              u32x2!(0x67452301, 0xefcdab89),
              u32x2!(0x98badcfe, 0x10325476),
@@ -339,8 +562,17 @@ impl_Digest!(SHA1, 160, 160, 512, 64, sha1_block_data_order,
              0, 0, 0, 0, 0,
 ]);
 
+impl_Digest!(SHA224, 224, 256, 512, 64, sha256_block_data_order,
+             sha256_acceleration, sha256_format_output, SHA224_INITIAL, [
+             u32x2!(0xc1059ed8, 0x367cd507),
+             u32x2!(0x3070dd17, 0xf70e5939),
+             u32x2!(0xffc00b31, 0x68581511),
+             u32x2!(0x64f98fa7, 0xbefa4fa4),
+             0, 0, 0, 0,
+]);
+
 impl_Digest!(SHA256, 256, 256, 512, 64, sha256_block_data_order,
-             sha256_format_output, SHA256_INITIAL, [
+             sha256_acceleration, sha256_format_output, SHA256_INITIAL, [
              u32x2!(0x6a09e667, 0xbb67ae85),
              u32x2!(0x3c6ef372, 0xa54ff53a),
              u32x2!(0x510e527f, 0x9b05688c),
@@ -349,7 +581,7 @@ impl_Digest!(SHA256, 256, 256, 512, 64, sha256_block_data_order,
 ]);
 
 impl_Digest!(SHA384, 384, 512, 1024, 128, sha512_block_data_order,
-             sha512_format_output, SHA384_INITIAL, [
+             no_acceleration, sha512_format_output, SHA384_INITIAL, [
              0xcbbb9d5dc1059ed8,
              0x629a292a367cd507,
              0x9159015a3070dd17,
@@ -361,7 +593,7 @@ impl_Digest!(SHA384, 384, 512, 1024, 128, sha512_block_data_order,
 ]);
 
 impl_Digest!(SHA512, 512, 512, 1024, 128, sha512_block_data_order,
-             sha512_format_output, SHA512_INITIAL, [
+             no_acceleration, sha512_format_output, SHA512_INITIAL, [
              0x6a09e667f3bcc908,
              0xbb67ae8584caa73b,
              0x3c6ef372fe94f82b,
@@ -372,6 +604,30 @@ impl_Digest!(SHA512, 512, 512, 1024, 128, sha512_block_data_order,
              0x5be0cd19137e2179,
 ]);
 
+impl_Digest!(SHA512_224, 224, 512, 1024, 128, sha512_block_data_order,
+             no_acceleration, sha512_format_output, SHA512_224_INITIAL, [
+             0x8c3d37c819544da2,
+             0x73e1996689dcd4d6,
+             0x1dfab7ae32ff9c82,
+             0x679dd514582f9fcf,
+             0x0f6d2b697bd44da8,
+             0x77e36f7304c48942,
+             0x3f9d85a86a1d36c8,
+             0x1112e6ad91d692a1,
+]);
+
+impl_Digest!(SHA512_256, 256, 512, 1024, 128, sha512_block_data_order,
+             no_acceleration, sha512_format_output, SHA512_256_INITIAL, [
+             0x22312194fc2bf72c,
+             0x9f555fa3c84c64c2,
+             0x2393b86b6f53b151,
+             0x963877195940eabd,
+             0x96283ee2a88effe3,
+             0xbe5e1e2553863992,
+             0x2b0199fc2c85b8aa,
+             0x0eb72ddc81c52ca2,
+]);
+
 /// The maximum block length (`Algorithm::block_len`) of all the algorithms in
 /// this module.
 pub const MAX_BLOCK_LEN: usize = 1024 / 8;
@@ -419,6 +675,13 @@ extern {
     fn sha512_block_data_order(state: *mut u64, data: *const u8, num: c::size_t);
 }
 
+// There is no accelerated `*_block_data_order_hw` assembly linked into this
+// crate yet (see `sha1_acceleration`/`sha256_acceleration` above), so there
+// are no corresponding `extern` declarations here. When SHA-NI (x86_64) or
+// ARMv8 crypto extension (aarch64) implementations are added, along with the
+// build-system plumbing to compile and link them, declare them here and wire
+// them back into `sha1_acceleration`/`sha256_acceleration`.
+
 #[cfg(test)]
 mod tests {
     use super::super::{digest, file_test};
@@ -446,6 +709,33 @@ mod tests {
         });
     }
 
+    /// Runs the same test vectors as `test_digests`, but with each `Context`
+    /// forced onto the portable implementation, so that the portable path
+    /// gets exercised even on CPUs that support a hardware-accelerated one.
+    ///
+    /// This forces the override on a per-`Context` basis rather than on the
+    /// shared `Algorithm`, so it can't race with `test_digests` (which is
+    /// supposed to exercise whatever path the current CPU picks normally)
+    /// running concurrently against the same algorithm.
+    #[test]
+    fn test_digests_portable() {
+        file_test::run("src/digest_tests.txt", |section, test_case| {
+            assert_eq!(section, "");
+            let digest_alg = test_case.consume_digest_alg("Hash").unwrap();
+            let input = test_case.consume_bytes("Input");
+            let repeat = test_case.consume_usize("Repeat");
+            let expected = test_case.consume_bytes("Output");
+
+            let mut ctx = digest::Context::new(digest_alg);
+            ctx.force_portable_for_testing();
+            for _ in 0..repeat {
+                ctx.update(&input);
+            }
+            let actual = ctx.finish();
+            assert_eq!(&expected, &actual.as_ref());
+        });
+    }
+
     /// Test some ways in which `Context::update` and/or `Context::finish`
     /// could go wrong by testing every combination of updating three inputs
     /// that vary from zero bytes to twice the size of the block length.
@@ -488,9 +778,12 @@ mod tests {
         }
     }
     test_i_u_f!(test_i_u_f_sha1, digest::SHA1);
+    test_i_u_f!(test_i_u_f_sha224, digest::SHA224);
     test_i_u_f!(test_i_u_f_sha256, digest::SHA256);
     test_i_u_f!(test_i_u_f_sha384, digest::SHA384);
     test_i_u_f!(test_i_u_f_sha512, digest::SHA512);
+    test_i_u_f!(test_i_u_f_sha512_224, digest::SHA512_224);
+    test_i_u_f!(test_i_u_f_sha512_256, digest::SHA512_256);
 
     /// See https://bugzilla.mozilla.org/show_bug.cgi?id=610162. This tests the
     /// calculation of 8GB of the byte 123.
@@ -565,4 +858,68 @@ mod tests {
         0x49, 0x1A, 0x6B, 0xEC, 0x9C, 0x98, 0xC8, 0x19,
         0xA6, 0xA9, 0x88, 0x3E, 0x2F, 0x09, 0xB9, 0x9A
     ]);
+
+    /// Splits `input` at every offset, exports the context after hashing
+    /// the first half, imports it back, hashes the second half, and checks
+    /// that the result matches the one-shot digest of the whole input.
+    macro_rules! test_export_import_state {
+        ( $test_name:ident, $alg:expr ) => {
+            #[test]
+            fn $test_name() {
+                let input = b"some input data that is hashed in two halves";
+                let one_shot = digest::digest(&$alg, input);
+
+                for split in 0..(input.len() + 1) {
+                    let (first, second) = input.split_at(split);
+
+                    let mut ctx = digest::Context::new(&$alg);
+                    ctx.update(first);
+
+                    let exported = ctx.export_state();
+                    let mut resumed =
+                        digest::Context::import_state(&exported).unwrap();
+                    resumed.update(second);
+
+                    let digest = resumed.finish();
+                    assert_eq!(one_shot.as_ref(), digest.as_ref());
+                }
+            }
+        }
+    }
+    test_export_import_state!(test_export_import_state_sha1, digest::SHA1);
+    test_export_import_state!(test_export_import_state_sha224,
+                              digest::SHA224);
+    test_export_import_state!(test_export_import_state_sha256,
+                              digest::SHA256);
+    test_export_import_state!(test_export_import_state_sha384,
+                              digest::SHA384);
+    test_export_import_state!(test_export_import_state_sha512,
+                              digest::SHA512);
+    test_export_import_state!(test_export_import_state_sha512_224,
+                              digest::SHA512_224);
+    test_export_import_state!(test_export_import_state_sha512_256,
+                              digest::SHA512_256);
+
+    #[test]
+    fn test_import_state_rejects_unknown_algorithm() {
+        let mut exported = digest::Context::new(&digest::SHA256)
+                                .export_state();
+        exported[0] = 0xff;
+        assert!(digest::Context::import_state(&exported).is_err());
+    }
+
+    #[test]
+    fn test_import_state_rejects_oversized_num_pending() {
+        let mut exported = digest::Context::new(&digest::SHA256)
+                                .export_state();
+        exported[9] = digest::SHA256.block_len as u8;
+        assert!(digest::Context::import_state(&exported).is_err());
+    }
+
+    #[test]
+    fn test_import_state_rejects_truncated_input() {
+        let exported = digest::Context::new(&digest::SHA256).export_state();
+        assert!(digest::Context::import_state(&exported[..exported.len() - 1])
+                    .is_err());
+    }
 }